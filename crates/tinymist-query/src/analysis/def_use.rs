@@ -1,10 +1,12 @@
 use core::fmt;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     ops::{Deref, Range},
     sync::Arc,
 };
 
+use arc_swap::ArcSwap;
+use crossbeam_channel::{Receiver, Sender};
 use log::info;
 use serde::Serialize;
 use typst::syntax::Source;
@@ -57,16 +59,30 @@ impl Serialize for IdentRef {
     }
 }
 
+/// Whether a binding can be seen from outside the file that defines it.
+/// Defaults to [`Visibility::Public`] for a `#let`-bound name sitting at a
+/// module's top level, and [`Visibility::Private`] for one nested inside a
+/// block or explicitly marked internal with a leading underscore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Visibility {
+    Public,
+    Private,
+}
+
 #[derive(Serialize, Clone)]
 pub struct IdentDef {
     pub name: String,
     pub kind: LexicalKind,
+    /// The file `range` is an offset into — not necessarily the file of
+    /// the `ident_defs` key this is stored under (see `backfill_binding`).
+    pub fid: TypstFileId,
     pub range: Range<usize>,
+    pub vis: Visibility,
 }
 
 type ExternalRefMap = HashMap<(TypstFileId, Option<String>), Vec<(Option<DefId>, IdentRef)>>;
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct DefUseInfo {
     ident_defs: indexmap::IndexMap<(TypstFileId, IdentRef), IdentDef>,
     external_refs: ExternalRefMap,
@@ -83,9 +99,12 @@ impl DefUseInfo {
         self.ident_refs.get(ident).copied()
     }
 
+    /// A `DefId` is only meaningful within the `DefUseInfo` it came from,
+    /// so callers holding one from elsewhere must resolve it back through
+    /// its own table rather than comparing indices across files directly.
     pub fn get_def_by_id(&self, id: DefId) -> Option<(TypstFileId, &IdentDef)> {
-        let ((fid, _), def) = self.ident_defs.get_index(id.0 as usize)?;
-        Some((*fid, def))
+        let (_, def) = self.ident_defs.get_index(id.0 as usize)?;
+        Some((def.fid, def))
     }
 
     pub fn get_def(&self, fid: TypstFileId, ident: &IdentRef) -> Option<(DefId, &IdentDef)> {
@@ -124,6 +143,19 @@ impl DefUseInfo {
     pub fn is_exported(&self, id: DefId) -> bool {
         self.exports_refs.contains(&id)
     }
+
+    /// Whether `def` can be referenced from `importer_fid`: either it's
+    /// defined right there, or it's `Visibility::Public` in its own file.
+    /// Meant to be called by completion and go-to-definition at the
+    /// request-handling layer to filter out a module's private helpers
+    /// across file boundaries; this module only computes the def-use graph
+    /// those requests are built on top of.
+    pub fn is_visible_from(&self, def: DefId, importer_fid: TypstFileId) -> bool {
+        let Some((owner, ident_def)) = self.get_def_by_id(def) else {
+            return false;
+        };
+        owner == importer_fid || ident_def.vis == Visibility::Public
+    }
 }
 
 pub fn get_def_use(ctx: &mut AnalysisContext, source: Source) -> Option<Arc<DefUseInfo>> {
@@ -139,96 +171,418 @@ fn get_def_use_inner(ctx: &mut SearchCtx, source: Source) -> Option<Arc<DefUseIn
         return Some(info);
     }
 
-    if !ctx.searched.insert(current_id) {
-        return None;
+    // Run the fixed-point resolver over the whole dependency graph reachable
+    // from `source`. This produces (possibly freshly computed) info for
+    // every file it touched along the way, not just `current_id`, so cache
+    // all of it rather than recomputing on the next request.
+    let infos = {
+        let mut resolver = FixedPointResolver::new(ctx);
+        resolver.run(source);
+        resolver.infos
+    };
+
+    let mut current = None;
+    for (fid, info) in infos {
+        let info = Arc::new(info);
+        if fid == current_id {
+            current = Some(info.clone());
+        }
+
+        ctx.ctx.get_mut(fid);
+        if let Some(c) = ctx.ctx.get(fid) {
+            c.compute_def_use(|| Some(info.clone()));
+        }
+    }
+
+    current
+}
+
+/// An import queued for `FixedPointResolver::run` to retry once its target
+/// settles: a named import whose target was still being scanned (a cycle),
+/// or any glob (`Star`) import. `name` is `None` for a glob import.
+struct PendingImport {
+    importing_id: TypstFileId,
+    ext_id: TypstFileId,
+    name: Option<String>,
+    ident_ref: IdentRef,
+}
+
+/// Caps the number of passes `FixedPointResolver::run` makes over the
+/// pending-import worklist. A real dependency graph converges in a couple
+/// of passes; this just bounds pathological cycles instead of looping
+/// forever.
+const MAX_FIXED_POINT_ITERS: usize = 128;
+
+/// Scans every file reachable from the entry point, draining `PendingImport`s
+/// pass by pass until the worklist stops making progress — so import cycles
+/// still get real def/use info instead of bailing out empty.
+struct FixedPointResolver<'a, 'b, 'w> {
+    ctx: &'a mut SearchCtx<'b, 'w>,
+    /// Per-file def-use info, re-enterable across passes: a file's entry
+    /// here may still contain placeholder bindings for unresolved imports
+    /// until the worklist drains.
+    infos: HashMap<TypstFileId, DefUseInfo>,
+    /// Files currently on the scanning stack. Re-entering one of these is a
+    /// cyclic import, handled by deferring to `worklist` instead of
+    /// recursing into `scan_file` again.
+    scanning: HashSet<TypstFileId>,
+    worklist: Vec<PendingImport>,
+}
+
+impl<'a, 'b, 'w> FixedPointResolver<'a, 'b, 'w> {
+    fn new(ctx: &'a mut SearchCtx<'b, 'w>) -> Self {
+        Self {
+            ctx,
+            infos: HashMap::new(),
+            scanning: HashSet::new(),
+            worklist: Vec::new(),
+        }
+    }
+
+    /// Scans `source` and everything it (transitively) imports, then drains
+    /// the pending-import worklist to a fixed point.
+    fn run(&mut self, source: Source) {
+        self.scan_file(source);
+
+        for _ in 0..MAX_FIXED_POINT_ITERS {
+            if !self.resolve_pending() {
+                break;
+            }
+        }
+
+        // Whatever is left never stabilized (a cycle that never bottoms
+        // out in a concrete binding). Treat it as an empty set so it
+        // terminates, and surface the site as undefined.
+        for pending in std::mem::take(&mut self.worklist) {
+            // Glob imports are re-queued on every pass (see
+            // `resolve_pending_once`), so one left here is not unresolved.
+            if pending.name.is_none() {
+                continue;
+            }
+            if let Some(info) = self.infos.get_mut(&pending.importing_id) {
+                info.undefined_refs.push(pending.ident_ref);
+            }
+        }
+    }
+
+    /// Scans a single file into a raw `DefUseInfo`, recursing into its
+    /// imports. A no-op if the file is already scanned or is currently on
+    /// the scanning stack (the cyclic case, left for the caller to notice
+    /// via `self.scanning`).
+    fn scan_file(&mut self, source: Source) {
+        let current_id = source.id();
+        if self.infos.contains_key(&current_id) || self.scanning.contains(&current_id) {
+            return;
+        }
+
+        // A dependency that's already fully resolved and cached doesn't
+        // need to be rescanned just because this is a fresh resolver run —
+        // only the file that actually changed should pay for a rescan.
+        self.ctx.ctx.get_mut(current_id);
+        if let Some(cached) = self.ctx.ctx.get(current_id).and_then(|c| c.def_use()) {
+            self.infos.insert(current_id, (*cached).clone());
+            return;
+        }
+
+        self.scanning.insert(current_id);
+
+        let info = match get_lexical_hierarchy(source, LexicalScopeKind::DefUse) {
+            Some(e) => {
+                let mut collector = DefUseCollector {
+                    resolver: self,
+                    info: DefUseInfo::default(),
+                    id_scope: SnapshotMap::default(),
+                    label_scope: SnapshotMap::default(),
+                    current_id,
+                    ext_src: None,
+                    depth: 0,
+                };
+
+                collector.info.redefine_current = Some(current_id);
+                collector.scan(&e);
+                collector.calc_exports();
+                collector.info
+            }
+            None => DefUseInfo::default(),
+        };
+
+        self.scanning.remove(&current_id);
+        self.infos.insert(current_id, info);
+    }
+
+    /// Scans `ext_id` if nothing has touched it yet. Leaves files already
+    /// scanned or currently scanning (a cycle) alone.
+    fn ensure_scanned(&mut self, ext_id: TypstFileId, ext_src: &Source) {
+        if !self.infos.contains_key(&ext_id) && !self.scanning.contains(&ext_id) {
+            self.scan_file(ext_src.clone());
+        }
+    }
+
+    /// Drains one pass over the worklist, resolving whatever has become
+    /// available. Returns whether any entry made progress, so `run` can
+    /// detect the fixpoint.
+    fn resolve_pending(&mut self) -> bool {
+        resolve_pending_once(&mut self.infos, &mut self.worklist)
+    }
+
+    /// Backfills a placeholder binding reserved at `(importing_id,
+    /// site)` with the dependency's real definition, once it's known.
+    fn backfill(&mut self, importing_id: TypstFileId, site: &IdentRef, ext_id: TypstFileId, def_id: DefId) {
+        backfill_binding(&mut self.infos, importing_id, site, ext_id, def_id)
+    }
+
+    /// Imports one export of a (possibly still-settling) glob import target.
+    fn backfill_star(&mut self, importing_id: TypstFileId, ext_id: TypstFileId, def_id: DefId) {
+        backfill_star_binding(&mut self.infos, importing_id, ext_id, def_id)
+    }
+}
+
+/// Drains one pass over `worklist` against the current state of `infos`,
+/// resolving whatever has become available. Returns whether any entry made
+/// progress. Free-standing (rather than a `FixedPointResolver` method) so
+/// the fixed-point convergence logic can be exercised directly in tests
+/// without needing a real `SearchCtx`.
+fn resolve_pending_once(
+    infos: &mut HashMap<TypstFileId, DefUseInfo>,
+    worklist: &mut Vec<PendingImport>,
+) -> bool {
+    let pending = std::mem::take(worklist);
+    let mut progress = false;
+
+    for p in pending {
+        match &p.name {
+            Some(name) => {
+                let def_id = infos
+                    .get(&p.ext_id)
+                    .and_then(|info| info.exports_defs.get(name).copied());
+
+                match def_id {
+                    Some(def_id) => {
+                        backfill_binding(infos, p.importing_id, &p.ident_ref, p.ext_id, def_id);
+                        progress = true;
+                    }
+                    None => worklist.push(p),
+                }
+            }
+            None => {
+                // Glob import: keep re-subscribing, since `ext_id`'s own
+                // exports may still be growing while its cyclic imports
+                // resolve. `backfill_star_binding` is idempotent (it keys on
+                // the original definition site), so re-running it each pass
+                // just confirms already-imported names.
+                let before = infos.get(&p.importing_id).map(|i| i.ident_defs.len());
+                if let Some(exports) = infos.get(&p.ext_id).map(|i| i.exports_refs.clone()) {
+                    for def_id in exports {
+                        backfill_star_binding(infos, p.importing_id, p.ext_id, def_id);
+                    }
+                }
+                let after = infos.get(&p.importing_id).map(|i| i.ident_defs.len());
+                if before != after {
+                    progress = true;
+                }
+                worklist.push(p);
+            }
+        }
     }
 
-    let e = get_lexical_hierarchy(source, LexicalScopeKind::DefUse)?;
+    progress
+}
 
-    let mut collector = DefUseCollector {
-        ctx,
-        info: DefUseInfo::default(),
-        id_scope: SnapshotMap::default(),
-        label_scope: SnapshotMap::default(),
+/// Backfills a placeholder binding reserved at `(importing_id, site)` with
+/// the dependency's real definition, once it's known. Only the value is
+/// replaced, never the key (`IndexMap::get_mut` only hands out
+/// `&mut V`) — the `DefId` stays put, so references resolved against the
+/// placeholder during the initial scan keep working, and the backfilled
+/// `IdentDef` carries its own `fid` so `get_def_by_id` still reports the
+/// dependency as owner.
+fn backfill_binding(
+    infos: &mut HashMap<TypstFileId, DefUseInfo>,
+    importing_id: TypstFileId,
+    site: &IdentRef,
+    ext_id: TypstFileId,
+    def_id: DefId,
+) {
+    let Some((_, ext_sym)) = infos.get(&ext_id).and_then(|i| i.get_def_by_id(def_id)) else {
+        return;
+    };
+    let ext_sym = ext_sym.clone();
 
-        current_id,
-        ext_src: None,
+    let Some(info) = infos.get_mut(&importing_id) else {
+        return;
     };
+    if let Some(value) = info.ident_defs.get_mut(&(importing_id, site.clone())) {
+        *value = ext_sym;
+    }
+}
 
-    collector.info.redefine_current = Some(current_id);
-    collector.scan(&e);
-    collector.calc_exports();
-    let res = Some(Arc::new(collector.info));
+/// Imports one export of a (possibly still-settling) glob import target.
+/// Unlike `backfill_binding`, there's no placeholder reserved ahead of
+/// time, so this inserts fresh. It runs only after the importing file has
+/// finished scanning, so that file's own references to a name pulled in
+/// this way are not bound to it (unlike the non-cyclic `Star` case, where
+/// `import_from` binds the name during the scan).
+fn backfill_star_binding(
+    infos: &mut HashMap<TypstFileId, DefUseInfo>,
+    importing_id: TypstFileId,
+    ext_id: TypstFileId,
+    def_id: DefId,
+) {
+    let Some((ext_fid, ext_sym)) = infos.get(&ext_id).and_then(|i| i.get_def_by_id(def_id)) else {
+        return;
+    };
+    let ext_sym = ext_sym.clone();
 
-    let c = ctx.ctx.get(current_id).unwrap();
-    // todo: cyclic import cause no any information
-    c.compute_def_use(|| res.clone());
-    res
+    let Some(info) = infos.get_mut(&importing_id) else {
+        return;
+    };
+    let ext_ref = IdentRef {
+        name: ext_sym.name.clone(),
+        range: ext_sym.range.clone(),
+    };
+    info.ident_defs.insert((ext_fid, ext_ref), ext_sym);
 }
 
-struct DefUseCollector<'a, 'b, 'w> {
-    ctx: &'a mut SearchCtx<'b, 'w>,
+struct DefUseCollector<'s, 'a, 'b, 'w> {
+    resolver: &'s mut FixedPointResolver<'a, 'b, 'w>,
     info: DefUseInfo,
     label_scope: SnapshotMap<String, DefId>,
     id_scope: SnapshotMap<String, DefId>,
 
     current_id: TypstFileId,
     ext_src: Option<Source>,
+    /// Block nesting depth; `0` is a module's top level. Used to default a
+    /// binding's visibility.
+    depth: usize,
 }
 
-impl<'a, 'b, 'w> DefUseCollector<'a, 'b, 'w> {
+impl<'s, 'a, 'b, 'w> DefUseCollector<'s, 'a, 'b, 'w> {
     fn enter<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
         let id_snap = self.id_scope.snapshot();
+        self.depth += 1;
         let res = f(self);
+        self.depth -= 1;
         self.id_scope.rollback_to(id_snap);
         res
     }
 
+    /// A `#let`-bound name is public unless it's nested inside a block or
+    /// marked internal with a leading underscore, following the convention
+    /// Typst itself uses for "private" identifiers.
+    fn visibility_for(&self, name: &str) -> Visibility {
+        if self.depth > 0 || name.starts_with('_') {
+            Visibility::Private
+        } else {
+            Visibility::Public
+        }
+    }
+
     fn calc_exports(&mut self) {
-        self.info.exports_refs = self.id_scope.values().copied().collect();
+        let ident_defs = &self.info.ident_defs;
+        let is_public = |def_id: &DefId| {
+            ident_defs
+                .get_index(def_id.0 as usize)
+                .is_some_and(|(_, def)| def.vis == Visibility::Public)
+        };
+
+        self.info.exports_refs = self
+            .id_scope
+            .values()
+            .copied()
+            .filter(is_public)
+            .collect();
         self.info.exports_defs = self
             .id_scope
             .entries()
+            .filter(|(_, v)| is_public(v))
             .map(|(k, v)| (k.clone(), *v))
             .collect();
     }
 
-    fn import_name(&mut self, name: &str) -> Option<()> {
-        let source = self.ext_src.as_ref()?;
-
-        log::debug!("import for def use: {:?}, name: {name}", source.id());
-        let (_, external_info) =
-            Some(source.id()).zip(get_def_use_inner(self.ctx, source.clone()))?;
+    /// Resolves `name` against the module currently loaded into
+    /// `self.ext_src`, returning `Some(())` once a binding for it exists
+    /// in `self.id_scope` — either because the dependency's export is
+    /// already known, or because `ext_id` turned out to be a cyclic import
+    /// and a placeholder was reserved for the fixed-point loop to fill in
+    /// later. Returns `None` when the name is genuinely not exported by the
+    /// dependency, in which case the caller treats it as an extern symbol.
+    fn resolve_import(&mut self, name: &str, site: &IdentRef, kind: LexicalKind) -> Option<()> {
+        let source = self.ext_src.clone()?;
+        let ext_id = source.id();
+
+        log::debug!("import for def use: {ext_id:?}, name: {name}");
+        self.resolver.ensure_scanned(ext_id, &source);
+
+        if let Some(def_id) = self
+            .resolver
+            .infos
+            .get(&ext_id)
+            .and_then(|info| info.exports_defs.get(name).copied())
+        {
+            let local_id = self.import_from(ext_id, def_id);
+            self.insert_extern(name.to_string(), site.range.clone(), Some(local_id));
+            return Some(());
+        }
 
-        let ext_id = external_info.exports_defs.get(name)?;
-        self.import_from(&external_info, *ext_id);
+        if self.resolver.scanning.contains(&ext_id) {
+            // `ext_id` is still on the scanning stack above us: a cyclic
+            // import. Reserve the binding now so the rest of this file's
+            // scan resolves against it, and let `FixedPointResolver::run`
+            // backfill the real definition once `ext_id`'s exports settle.
+            let (id, ..) = self.info.ident_defs.insert_full(
+                (self.current_id, site.clone()),
+                IdentDef {
+                    name: name.to_string(),
+                    kind,
+                    fid: self.current_id,
+                    range: site.range.clone(),
+                    vis: self.visibility_for(name),
+                },
+            );
+            let id = DefId(id as u64);
+            self.id_scope.insert(name.to_string(), id);
+            self.insert_extern(name.to_string(), site.range.clone(), Some(id));
+
+            self.resolver.worklist.push(PendingImport {
+                importing_id: self.current_id,
+                ext_id,
+                name: Some(name.to_string()),
+                ident_ref: site.clone(),
+            });
+
+            return Some(());
+        }
 
-        Some(())
+        None
     }
 
-    fn import_from(&mut self, external_info: &DefUseInfo, v: DefId) {
+    fn import_from(&mut self, ext_id: TypstFileId, def_id: DefId) -> DefId {
         // Use FileId in ident_defs map should lose stacked import
         // information, but it is currently
         // not a problem.
-        let ((ext_id, _), ext_sym) = external_info.ident_defs.get_index(v.0 as usize).unwrap();
+        let Some((ext_fid, ext_sym)) = self
+            .resolver
+            .infos
+            .get(&ext_id)
+            .and_then(|info| info.get_def_by_id(def_id))
+        else {
+            return def_id;
+        };
+        let ext_sym = ext_sym.clone();
 
         let name = ext_sym.name.clone();
-
         let ext_ref = IdentRef {
             name: name.clone(),
             range: ext_sym.range.clone(),
         };
 
-        let (id, ..) = self
-            .info
-            .ident_defs
-            .insert_full((*ext_id, ext_ref), ext_sym.clone());
+        let (id, ..) = self.info.ident_defs.insert_full((ext_fid, ext_ref), ext_sym);
 
         let id = DefId(id as u64);
         self.id_scope.insert(name, id);
+        id
     }
 
-    fn scan(&mut self, e: &'a [LexicalHierarchy]) -> Option<()> {
+    fn scan(&mut self, e: &[LexicalHierarchy]) -> Option<()> {
         for e in e {
             match &e.info.kind {
                 LexicalKind::Heading(..) => unreachable!(),
@@ -245,7 +599,11 @@ impl<'a, 'b, 'w> DefUseCollector<'a, 'b, 'w> {
                     self.insert_module(Ns::Value, e)
                 }
                 LexicalKind::Mod(super::LexicalModKind::Ident) => {
-                    match self.import_name(&e.info.name) {
+                    let site = IdentRef {
+                        name: e.info.name.clone(),
+                        range: e.info.range.clone(),
+                    };
+                    match self.resolve_import(&e.info.name, &site, e.info.kind.clone()) {
                         Some(()) => {
                             self.insert_ref(Ns::Value, e);
                             self.insert_redef(e);
@@ -261,15 +619,13 @@ impl<'a, 'b, 'w> DefUseCollector<'a, 'b, 'w> {
                     }
                 }
                 LexicalKind::Mod(super::LexicalModKind::Alias { target }) => {
-                    match self.import_name(&target.name) {
+                    let site = IdentRef {
+                        name: target.name.clone(),
+                        range: target.range.clone(),
+                    };
+                    match self.resolve_import(&target.name, &site, e.info.kind.clone()) {
                         Some(()) => {
-                            self.insert_ident_ref(
-                                Ns::Value,
-                                IdentRef {
-                                    name: target.name.clone(),
-                                    range: target.range.clone(),
-                                },
-                            );
+                            self.insert_ident_ref(Ns::Value, site);
                             self.insert(Ns::Value, e);
                         }
                         None => {
@@ -293,7 +649,7 @@ impl<'a, 'b, 'w> DefUseCollector<'a, 'b, 'w> {
                         ModSrc::Expr(_) => {}
                         ModSrc::Path(p) => {
                             let src = find_source_by_import_path(
-                                self.ctx.ctx.world,
+                                self.resolver.ctx.ctx.world,
                                 self.current_id,
                                 p.deref(),
                             );
@@ -309,14 +665,42 @@ impl<'a, 'b, 'w> DefUseCollector<'a, 'b, 'w> {
                     self.ext_src = None;
                 }
                 LexicalKind::Mod(super::LexicalModKind::Star) => {
-                    if let Some(source) = &self.ext_src {
-                        info!("diving source for def use: {:?}", source.id());
-                        let (_, external_info) =
-                            Some(source.id()).zip(get_def_use_inner(self.ctx, source.clone()))?;
-
-                        for ext_id in &external_info.exports_refs {
-                            self.import_from(&external_info, *ext_id);
+                    if let Some(source) = self.ext_src.clone() {
+                        let ext_id = source.id();
+                        info!("diving source for def use: {ext_id:?}");
+                        self.resolver.ensure_scanned(ext_id, &source);
+
+                        if let Some(exports) =
+                            self.resolver.infos.get(&ext_id).map(|i| i.exports_refs.clone())
+                        {
+                            for ext_def_id in exports {
+                                let local_id = self.import_from(ext_id, ext_def_id);
+                                if let Some(name) = self
+                                    .resolver
+                                    .infos
+                                    .get(&ext_id)
+                                    .and_then(|i| i.get_def_by_id(ext_def_id))
+                                    .map(|(_, def)| def.name.clone())
+                                {
+                                    self.insert_extern(name, e.info.range.clone(), Some(local_id));
+                                }
+                            }
                         }
+
+                        // Subscribe regardless of whether `ext_id` is a
+                        // cycle: if it's still scanning (or has its own
+                        // pending imports), its export set can keep
+                        // growing, and the fixed-point loop re-applies this
+                        // entry on every pass to pick that up.
+                        self.resolver.worklist.push(PendingImport {
+                            importing_id: self.current_id,
+                            ext_id,
+                            name: None,
+                            ident_ref: IdentRef {
+                                name: "*".to_string(),
+                                range: e.info.range.clone(),
+                            },
+                        });
                     }
                 }
             }
@@ -328,29 +712,32 @@ impl<'a, 'b, 'w> DefUseCollector<'a, 'b, 'w> {
     fn insert_module(&mut self, label: Ns, e: &LexicalHierarchy) {
         self.insert(label, e);
         if let Some(src) = &self.ext_src {
-            self.info.external_refs.insert(
-                (src.id(), None),
-                vec![(
+            self.info
+                .external_refs
+                .entry((src.id(), None))
+                .or_default()
+                .push((
                     None,
                     IdentRef {
                         name: e.info.name.clone(),
                         range: e.info.range.clone(),
                     },
-                )],
-            );
+                ));
         }
     }
 
     fn insert_extern(&mut self, name: String, range: Range<usize>, redefine_id: Option<DefId>) {
         if let Some(src) = &self.ext_src {
-            self.info.external_refs.insert(
-                (src.id(), Some(name.clone())),
-                vec![(redefine_id, IdentRef { name, range })],
-            );
+            self.info
+                .external_refs
+                .entry((src.id(), Some(name.clone())))
+                .or_default()
+                .push((redefine_id, IdentRef { name, range }));
         }
     }
 
     fn insert(&mut self, label: Ns, e: &LexicalHierarchy) -> DefId {
+        let vis = self.visibility_for(&e.info.name);
         let snap = match label {
             Ns::Label => &mut self.label_scope,
             Ns::Value => &mut self.id_scope,
@@ -365,7 +752,9 @@ impl<'a, 'b, 'w> DefUseCollector<'a, 'b, 'w> {
             IdentDef {
                 name: e.info.name.clone(),
                 kind: e.info.kind.clone(),
+                fid: self.current_id,
                 range: e.info.range.clone(),
+                vis,
             },
         );
 
@@ -414,6 +803,414 @@ impl<'a, 'b, 'w> DefUseCollector<'a, 'b, 'w> {
     }
 }
 
+/// A single-file edit produced by [`ReferenceIndex::rename`]: replace the
+/// text at `range` with `new_text`.
+pub struct TextEdit {
+    pub range: Range<usize>,
+    pub new_text: String,
+}
+
+/// Folds one importing file's cross-file reference sites for `def_fid`'s
+/// exports into `by_def`: both the `#import` line itself, and (via
+/// `get_refs` on the local id it's bound to) any later local use of the
+/// imported name.
+fn collect_import_sites(
+    importer_info: &DefUseInfo,
+    importer_fid: TypstFileId,
+    by_def: &mut HashMap<(TypstFileId, String), Vec<(TypstFileId, IdentRef)>>,
+) {
+    for ((def_fid, name), refs) in &importer_info.external_refs {
+        let Some(name) = name else { continue };
+        for (local_id, ident_ref) in refs {
+            let Some(local_id) = local_id else {
+                by_def
+                    .entry((*def_fid, name.clone()))
+                    .or_default()
+                    .push((importer_fid, ident_ref.clone()));
+                continue;
+            };
+
+            for local_ref in importer_info.get_refs(*local_id) {
+                by_def
+                    .entry((*def_fid, name.clone()))
+                    .or_default()
+                    .push((importer_fid, local_ref.clone()));
+            }
+        }
+    }
+}
+
+/// A project-wide index of who references which exported definition;
+/// `DefUseInfo::get_refs` only sees references within its own file, so this
+/// backs the editor's find-all-references and rename requests instead.
+pub struct ReferenceIndex {
+    /// `(def_fid, name) -> [(referencing file, reference site)]`.
+    by_def: HashMap<(TypstFileId, String), Vec<(TypstFileId, IdentRef)>>,
+}
+
+impl ReferenceIndex {
+    /// Builds the index by computing (or reusing the cached) def-use info
+    /// of every file in `sources` and folding in its import/reference
+    /// sites via [`collect_import_sites`].
+    pub fn build(ctx: &mut AnalysisContext, sources: impl IntoIterator<Item = Source>) -> Self {
+        let mut by_def: HashMap<(TypstFileId, String), Vec<(TypstFileId, IdentRef)>> =
+            HashMap::new();
+
+        for source in sources {
+            let importer_fid = source.id();
+            let Some(info) = get_def_use(ctx, source) else {
+                continue;
+            };
+
+            collect_import_sites(&info, importer_fid, &mut by_def);
+        }
+
+        Self { by_def }
+    }
+
+    /// All references to the export named `ident.name` defined in
+    /// `def_fid`, across the whole workspace, including the definition
+    /// site itself.
+    pub fn find_references(
+        &self,
+        ctx: &mut AnalysisContext,
+        def_fid: TypstFileId,
+        ident: &IdentRef,
+    ) -> Option<Vec<(TypstFileId, Range<usize>)>> {
+        let def_source = ctx.source_by_id(def_fid)?;
+        let def_info = get_def_use(ctx, def_source)?;
+        let (def_id, def) = def_info.get_def(def_fid, ident)?;
+
+        let mut sites = vec![(def_fid, def.range.clone())];
+        sites.extend(def_info.get_refs(def_id).map(|r| (def_fid, r.range.clone())));
+        if let Some(refs) = self.by_def.get(&(def_fid, ident.name.clone())) {
+            sites.extend(refs.iter().map(|(fid, r)| (*fid, r.range.clone())));
+        }
+        Some(sites)
+    }
+
+    /// Validates that `ident` (defined in `def_fid`) can be renamed at
+    /// all, i.e. that it still resolves to a definition.
+    pub fn prepare_rename(
+        &self,
+        ctx: &mut AnalysisContext,
+        def_fid: TypstFileId,
+        ident: &IdentRef,
+    ) -> Option<()> {
+        let def_source = ctx.source_by_id(def_fid)?;
+        let def_info = get_def_use(ctx, def_source)?;
+        def_info.get_def(def_fid, ident)?;
+        Some(())
+    }
+
+    /// Renames every reference (and the definition itself) to `new_name`,
+    /// rejecting the rename if `new_name` is already exported as a distinct
+    /// binding by any file it touches. Only exports are checked: a
+    /// non-exported binding of that name (nested in a block, or private) is
+    /// not detected as a collision.
+    pub fn rename(
+        &self,
+        ctx: &mut AnalysisContext,
+        def_fid: TypstFileId,
+        ident: &IdentRef,
+        new_name: &str,
+    ) -> Option<HashMap<TypstFileId, Vec<TextEdit>>> {
+        let def_source = ctx.source_by_id(def_fid)?;
+        let def_info = get_def_use(ctx, def_source)?;
+        def_info.get_def(def_fid, ident)?;
+
+        let sites = self.find_references(ctx, def_fid, ident)?;
+
+        let mut edits: HashMap<TypstFileId, Vec<TextEdit>> = HashMap::new();
+        for (fid, range) in sites {
+            let source = ctx.source_by_id(fid)?;
+            let info = get_def_use(ctx, source)?;
+            if renaming_collides(&info, new_name, def_fid, &ident.name) {
+                return None;
+            }
+
+            edits.entry(fid).or_default().push(TextEdit {
+                range,
+                new_text: new_name.to_string(),
+            });
+        }
+
+        Some(edits)
+    }
+}
+
+/// Whether binding `new_name` in `info`, if it exists, is a different
+/// definition from `(def_fid, def_name)` (see [`DefUseInfo::get_def_by_id`]
+/// for why this has to resolve through the id rather than compare it raw).
+fn renaming_collides(info: &DefUseInfo, new_name: &str, def_fid: TypstFileId, def_name: &str) -> bool {
+    info.exports_defs.get(new_name).is_some_and(|existing| {
+        info.get_def_by_id(*existing)
+            .map_or(true, |(owner, def)| owner != def_fid || def.name != def_name)
+    })
+}
+
+/// One exported symbol aggregated by [`SymbolIndex`]. `fid` is carried
+/// alongside `def_id` so callers can resolve it (see
+/// [`DefUseInfo::get_def_by_id`]).
+#[derive(Debug, Clone)]
+pub struct SymbolInfo {
+    pub name: String,
+    pub fid: TypstFileId,
+    pub def_id: DefId,
+    pub kind: LexicalKind,
+    pub range: Range<usize>,
+}
+
+/// A flat, fuzzy-searchable aggregation of every file's
+/// `DefUseInfo::exports_defs`. Meant to back the editor's "workspace
+/// symbols" request, which otherwise has no way to search across files;
+/// callers keep it current by calling `update`/`remove_file` as files are
+/// (re)analyzed or drop out of the workspace.
+#[derive(Default)]
+pub struct SymbolIndex {
+    symbols: Vec<SymbolInfo>,
+}
+
+impl SymbolIndex {
+    /// Replaces whatever was previously indexed for `fid` with its current
+    /// exports, so the index can be kept incrementally up to date as files
+    /// are reanalyzed instead of requiring a full rebuild.
+    pub fn update(&mut self, fid: TypstFileId, info: &DefUseInfo) {
+        self.remove_file(fid);
+        for (name, def_id) in &info.exports_defs {
+            let Some((_, def)) = info.get_def_by_id(*def_id) else {
+                continue;
+            };
+            self.symbols.push(SymbolInfo {
+                name: name.clone(),
+                fid,
+                def_id: *def_id,
+                kind: def.kind.clone(),
+                range: def.range.clone(),
+            });
+        }
+    }
+
+    /// Drops every symbol indexed for `fid`, e.g. when it's removed from
+    /// the workspace.
+    pub fn remove_file(&mut self, fid: TypstFileId) {
+        self.symbols.retain(|s| s.fid != fid);
+    }
+
+    /// Subsequence/fuzzy search for `pattern` over every indexed symbol
+    /// whose kind passes `kind_filter`, best match first.
+    pub fn query(
+        &self,
+        pattern: &str,
+        kind_filter: impl Fn(&LexicalKind) -> bool,
+    ) -> Vec<SymbolInfo> {
+        let pattern = pattern.to_lowercase();
+        let mut scored: Vec<(i32, &SymbolInfo)> = self
+            .symbols
+            .iter()
+            .filter(|s| kind_filter(&s.kind))
+            .filter_map(|s| {
+                subsequence_score(&s.name.to_lowercase(), &pattern).map(|score| (score, s))
+            })
+            .collect();
+
+        scored.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.name.cmp(&b.1.name)));
+        scored.into_iter().map(|(_, s)| s.clone()).collect()
+    }
+}
+
+/// Subsequence fuzzy match: every character of `pattern` must appear in
+/// `name` in order, not necessarily contiguously. Returns `None` on no
+/// match, otherwise a score that rewards contiguous runs and a match at the
+/// very start of `name`, so tighter matches sort first in `SymbolIndex::query`.
+fn subsequence_score(name: &str, pattern: &str) -> Option<i32> {
+    if pattern.is_empty() {
+        return Some(0);
+    }
+
+    let mut score = 0;
+    let mut prev_end = None;
+    let mut pattern_chars = pattern.chars();
+    let mut next_pat = pattern_chars.next();
+
+    for (idx, c) in name.char_indices() {
+        let Some(p) = next_pat else { break };
+        if c == p {
+            score += if prev_end == Some(idx) { 2 } else { 1 };
+            if idx == 0 {
+                score += 1;
+            }
+            prev_end = Some(idx + c.len_utf8());
+            next_pat = pattern_chars.next();
+        }
+    }
+
+    if next_pat.is_some() {
+        None
+    } else {
+        Some(score)
+    }
+}
+
+/// A message driving [`DefUseWorker`]'s background loop: edits invalidate a
+/// file, and a fresh edit can cancel whatever recomputation is currently in
+/// flight.
+pub enum StateChange {
+    Invalidate(TypstFileId),
+    Cancel,
+}
+
+/// The (non-`Send`) pieces of computing `DefUseInfo` for one file, handed
+/// to [`DefUseWorker::spawn`] as owned, thread-safe closures so the worker
+/// thread never has to touch `AnalysisContext`/`SearchCtx` directly.
+pub struct DefUseRecompute {
+    pub source_by_id: Box<dyn Fn(TypstFileId) -> Option<Source> + Send + Sync>,
+    pub importers_of: Box<dyn Fn(TypstFileId) -> Vec<TypstFileId> + Send + Sync>,
+    pub compute: Box<dyn Fn(Source) -> Option<DefUseInfo> + Send + Sync>,
+}
+
+/// Recomputes def-use info for edited files on a background thread, so a
+/// burst of edits doesn't block the request that triggered it. `get`
+/// always returns the last published snapshot rather than waiting on an
+/// in-flight recomputation.
+pub struct DefUseWorker {
+    sender: Sender<StateChange>,
+    snapshot: Arc<ArcSwap<HashMap<TypstFileId, Arc<DefUseInfo>>>>,
+    _handle: std::thread::JoinHandle<()>,
+}
+
+impl DefUseWorker {
+    pub fn spawn(recompute: DefUseRecompute) -> Self {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let snapshot: Arc<ArcSwap<HashMap<TypstFileId, Arc<DefUseInfo>>>> =
+            Arc::new(ArcSwap::from_pointee(HashMap::new()));
+
+        let worker_snapshot = snapshot.clone();
+        let _handle = std::thread::spawn(move || Self::run(receiver, recompute, worker_snapshot));
+
+        Self {
+            sender,
+            snapshot,
+            _handle,
+        }
+    }
+
+    /// Queues recomputation of `fid` and its transitive importers.
+    pub fn invalidate(&self, fid: TypstFileId) {
+        let _ = self.sender.send(StateChange::Invalidate(fid));
+    }
+
+    /// Aborts whatever the worker is currently recomputing, without
+    /// queuing a replacement.
+    pub fn cancel(&self) {
+        let _ = self.sender.send(StateChange::Cancel);
+    }
+
+    /// The last fully-recomputed info for `fid`, if any. Never blocks: a
+    /// recomputation in flight just means this returns a slightly stale
+    /// snapshot until the worker publishes its next one.
+    pub fn get(&self, fid: TypstFileId) -> Option<Arc<DefUseInfo>> {
+        self.snapshot.load().get(&fid).cloned()
+    }
+
+    fn run(
+        receiver: Receiver<StateChange>,
+        recompute: DefUseRecompute,
+        snapshot: Arc<ArcSwap<HashMap<TypstFileId, Arc<DefUseInfo>>>>,
+    ) {
+        while let Ok(first) = receiver.recv() {
+            // Coalesce anything that piled up while we were idle into a
+            // dirty set rather than keeping only the most recent message —
+            // a burst of edits to files A and B must recompute both, not
+            // just whichever invalidation happened to arrive last.
+            let mut dirty = HashSet::new();
+            accumulate_dirty(first, &mut dirty);
+            while let Ok(next) = receiver.try_recv() {
+                accumulate_dirty(next, &mut dirty);
+            }
+
+            if dirty.is_empty() {
+                continue;
+            }
+
+            let Some(fresh) = run_batch(dirty, &receiver, &recompute) else {
+                // A `Cancel` arrived mid-batch: abort without publishing,
+                // per `cancel`'s contract of dropping whatever's in flight
+                // without a replacement.
+                continue;
+            };
+
+            if fresh.is_empty() {
+                continue;
+            }
+
+            snapshot.rcu(|prev| {
+                let mut next = (**prev).clone();
+                next.extend(fresh.clone());
+                next
+            });
+        }
+    }
+}
+
+/// Drains `dirty`'s worklist, recomputing each file (and queuing its
+/// importers) until nothing is left. An `Invalidate` that arrives mid-batch
+/// is merged in (the file it names is requeued, its stale `fresh` entry
+/// dropped) rather than discarding results already computed for other
+/// files. A `Cancel` still aborts the whole batch (`None`).
+fn run_batch(
+    dirty: HashSet<TypstFileId>,
+    receiver: &Receiver<StateChange>,
+    recompute: &DefUseRecompute,
+) -> Option<HashMap<TypstFileId, Arc<DefUseInfo>>> {
+    let mut worklist: Vec<_> = dirty.into_iter().collect();
+    let mut seen = HashSet::new();
+    let mut fresh = HashMap::new();
+
+    loop {
+        while let Ok(change) = receiver.try_recv() {
+            match change {
+                StateChange::Cancel => return None,
+                StateChange::Invalidate(fid) => {
+                    fresh.remove(&fid);
+                    seen.remove(&fid);
+                    worklist.push(fid);
+                }
+            }
+        }
+
+        let Some(next) = worklist.pop() else {
+            break;
+        };
+        if !seen.insert(next) {
+            continue;
+        }
+        let Some(source) = (recompute.source_by_id)(next) else {
+            continue;
+        };
+        let Some(info) = (recompute.compute)(source) else {
+            continue;
+        };
+        worklist.extend((recompute.importers_of)(next));
+        fresh.insert(next, Arc::new(info));
+    }
+
+    Some(fresh)
+}
+
+/// Folds one drained [`StateChange`] into the accumulated dirty set: an
+/// `Invalidate` adds a file, a `Cancel` drops everything accumulated so far
+/// so that a cancel sent after a run of invalidations actually cancels
+/// them, rather than just being the thing that happens to collapse onto.
+fn accumulate_dirty(change: StateChange, dirty: &mut HashSet<TypstFileId>) {
+    match change {
+        StateChange::Invalidate(fid) => {
+            dirty.insert(fid);
+        }
+        StateChange::Cancel => dirty.clear(),
+    }
+}
+
 pub struct DefUseSnapshot<'a>(pub &'a DefUseInfo);
 
 impl<'a> Serialize for DefUseSnapshot<'a> {
@@ -465,7 +1262,12 @@ impl<'a> Serialize for DefUseSnapshot<'a> {
                 def: &IdentDef {
                     name: "<nil>".to_string(),
                     kind: LexicalKind::Block,
+                    // `undefined_refs` is only ever populated by a real scan
+                    // (see `FixedPointResolver::run`), which always sets
+                    // `redefine_current` first.
+                    fid: self.0.redefine_current.expect("scanned file has a current id"),
                     range: 0..0,
+                    vis: Visibility::Private,
                 },
                 refs: &undefined_refs,
             };
@@ -475,3 +1277,444 @@ impl<'a> Serialize for DefUseSnapshot<'a> {
         state.end()
     }
 }
+
+/// Fixture factories shared by this file's test modules, so each one
+/// doesn't re-paste its own copy of "make a file id"/"make an ident
+/// ref"/"make a binding".
+#[cfg(test)]
+mod test_support {
+    use typst::syntax::VirtualPath;
+
+    use super::*;
+
+    pub(super) fn fid(path: &str) -> TypstFileId {
+        TypstFileId::new(None, VirtualPath::new(path))
+    }
+
+    pub(super) fn ident(name: &str) -> IdentRef {
+        IdentRef {
+            name: name.to_string(),
+            range: 0..name.len(),
+        }
+    }
+
+    pub(super) fn def(owner: TypstFileId, name: &str, vis: Visibility) -> IdentDef {
+        IdentDef {
+            name: name.to_string(),
+            kind: LexicalKind::Block,
+            fid: owner,
+            range: 0..name.len(),
+            vis,
+        }
+    }
+}
+
+#[cfg(test)]
+mod fixed_point_tests {
+    use super::test_support::*;
+    use super::*;
+
+    /// Two files each importing a named binding from the other should both
+    /// get backfilled with the real definition, and at the *original*
+    /// placeholder key — exercises that `backfill_binding` only replaces
+    /// the value, never the key, of the reserved `(importing_id, site)`
+    /// entry. Critically, `get_def_by_id` (which callers like
+    /// `SymbolIndex`/`ReferenceIndex` use to locate the definition's
+    /// source range) must report the *dependency* as the owning file after
+    /// backfill, not the importer whose key it's still stored under —
+    /// otherwise a caller opens the wrong file at the wrong range.
+    #[test]
+    fn resolves_mutual_named_imports() {
+        let a = fid("/a.typ");
+        let b = fid("/b.typ");
+
+        let site_a = ident("y"); // a.typ: `#import "b.typ": y`
+        let site_b = ident("x"); // b.typ: `#import "a.typ": x`
+
+        let mut infos = HashMap::new();
+
+        let mut info_a = DefUseInfo::default();
+        let (x_idx, _) = info_a
+            .ident_defs
+            .insert_full((a, ident("x")), def(a, "x", Visibility::Public));
+        info_a.exports_defs.insert("x".to_string(), DefId(x_idx as u64));
+        // Placeholder reserved during the initial scan, before `b` is
+        // known to export `y`: owned by `a` (the importer) until backfilled.
+        info_a
+            .ident_defs
+            .insert((a, site_a.clone()), def(a, "y", Visibility::Private));
+        infos.insert(a, info_a);
+
+        let mut info_b = DefUseInfo::default();
+        let (y_idx, _) = info_b
+            .ident_defs
+            .insert_full((b, ident("y")), def(b, "y", Visibility::Public));
+        info_b.exports_defs.insert("y".to_string(), DefId(y_idx as u64));
+        info_b
+            .ident_defs
+            .insert((b, site_b.clone()), def(b, "x", Visibility::Private));
+        infos.insert(b, info_b);
+
+        let mut worklist = vec![
+            PendingImport {
+                importing_id: a,
+                ext_id: b,
+                name: Some("y".to_string()),
+                ident_ref: site_a.clone(),
+            },
+            PendingImport {
+                importing_id: b,
+                ext_id: a,
+                name: Some("x".to_string()),
+                ident_ref: site_b.clone(),
+            },
+        ];
+
+        for _ in 0..MAX_FIXED_POINT_ITERS {
+            if !resolve_pending_once(&mut infos, &mut worklist) {
+                break;
+            }
+        }
+
+        assert!(worklist.is_empty(), "both mutual imports should resolve");
+
+        let (y_id, backfilled_y) = infos[&a]
+            .get_def(a, &site_a)
+            .expect("placeholder stays keyed at the original import site");
+        assert_eq!(backfilled_y.name, "y");
+        assert_eq!(backfilled_y.vis, Visibility::Public);
+        let (owner, _) = infos[&a]
+            .get_def_by_id(y_id)
+            .expect("backfilled def still resolves by id");
+        assert_eq!(owner, b, "get_def_by_id must report the dependency as owner, not the importer");
+
+        let (x_id, backfilled_x) = infos[&b]
+            .get_def(b, &site_b)
+            .expect("placeholder stays keyed at the original import site");
+        assert_eq!(backfilled_x.name, "x");
+        assert_eq!(backfilled_x.vis, Visibility::Public);
+        let (owner, _) = infos[&b]
+            .get_def_by_id(x_id)
+            .expect("backfilled def still resolves by id");
+        assert_eq!(owner, a, "get_def_by_id must report the dependency as owner, not the importer");
+    }
+
+    /// An import of a name the dependency doesn't export (yet) must stay on
+    /// the worklist rather than being dropped or silently resolved.
+    #[test]
+    fn pending_import_waits_for_export() {
+        let a = fid("/a.typ");
+        let b = fid("/b.typ");
+
+        let mut infos = HashMap::new();
+        infos.insert(a, DefUseInfo::default());
+        infos.insert(b, DefUseInfo::default());
+
+        let mut worklist = vec![PendingImport {
+            importing_id: a,
+            ext_id: b,
+            name: Some("missing".to_string()),
+            ident_ref: ident("missing"),
+        }];
+
+        let progress = resolve_pending_once(&mut infos, &mut worklist);
+        assert!(!progress);
+        assert_eq!(worklist.len(), 1);
+    }
+
+    /// An `Invalidate` for an unrelated file arriving while `a` is still
+    /// being recomputed must not throw away `a`'s already-finished result —
+    /// it should just get folded into this batch's worklist alongside it.
+    #[test]
+    fn mid_batch_invalidate_keeps_unrelated_already_computed_results() {
+        let a = fid("/a.typ");
+        let c = fid("/c.typ");
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let recompute = DefUseRecompute {
+            source_by_id: Box::new(|_| Some(Source::detached(""))),
+            importers_of: Box::new(|_| Vec::new()),
+            compute: {
+                let sender = sender.clone();
+                let c = c;
+                let sent = std::sync::atomic::AtomicBool::new(false);
+                Box::new(move |_| {
+                    // Simulate one unrelated edit to `c` arriving while
+                    // the first file is mid-recompute.
+                    if !sent.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                        let _ = sender.send(StateChange::Invalidate(c));
+                    }
+                    Some(DefUseInfo::default())
+                })
+            },
+        };
+
+        let dirty = HashSet::from([a]);
+        let fresh = run_batch(dirty, &receiver, &recompute)
+            .expect("no Cancel arrived, batch must publish");
+
+        assert!(fresh.contains_key(&a), "a's already-finished result must survive");
+        assert!(fresh.contains_key(&c), "c must be picked up too, not dropped for next batch");
+    }
+
+    /// A `Cancel` arriving mid-batch still aborts the whole batch, matching
+    /// `DefUseWorker::cancel`'s contract of dropping in-flight work without
+    /// queuing a replacement.
+    #[test]
+    fn mid_batch_cancel_aborts_the_whole_batch() {
+        let a = fid("/a.typ");
+        let d = fid("/d.typ");
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let recompute = DefUseRecompute {
+            source_by_id: Box::new(|_| Some(Source::detached(""))),
+            importers_of: Box::new(|_| Vec::new()),
+            compute: Box::new(move |_| {
+                let _ = sender.send(StateChange::Cancel);
+                Some(DefUseInfo::default())
+            }),
+        };
+
+        let dirty = HashSet::from([a, d]);
+        let fresh = run_batch(dirty, &receiver, &recompute);
+
+        assert!(fresh.is_none(), "a Cancel must abort publishing entirely");
+    }
+}
+
+#[cfg(test)]
+mod reference_index_tests {
+    use super::test_support::*;
+    use super::*;
+
+    /// `a.typ` does `#import "b.typ": y` and then calls `y()` twice more
+    /// later on. The import line itself lands in `external_refs`; the two
+    /// later calls are only visible through `a`'s own `ident_refs` against
+    /// the import's local placeholder `DefId` — `collect_import_sites` must
+    /// pull both kinds in, not just the import line.
+    #[test]
+    fn local_uses_of_an_import_are_folded_in_alongside_the_import_site() {
+        let a = fid("/a.typ");
+        let b = fid("/b.typ");
+
+        let mut info_a = DefUseInfo::default();
+        let import_site = ident("y");
+        let (local_id, ..) = info_a.ident_defs.insert_full(
+            (a, import_site.clone()),
+            IdentDef {
+                name: "y".to_string(),
+                kind: LexicalKind::Block,
+                fid: b,
+                range: 0..1,
+                vis: Visibility::Private,
+            },
+        );
+        let local_id = DefId(local_id as u64);
+
+        // The import line itself, recorded the way `resolve_import` +
+        // `insert_extern` record it.
+        info_a
+            .external_refs
+            .entry((b, Some("y".to_string())))
+            .or_default()
+            .push((Some(local_id), import_site.clone()));
+        info_a.ident_refs.insert(import_site.clone(), local_id);
+
+        // Two later ordinary calls to `y()`, bound the way `insert_ref`
+        // binds any other local reference.
+        let call_site_1 = IdentRef { name: "y".to_string(), range: 10..11 };
+        let call_site_2 = IdentRef { name: "y".to_string(), range: 20..21 };
+        info_a.ident_refs.insert(call_site_1.clone(), local_id);
+        info_a.ident_refs.insert(call_site_2.clone(), local_id);
+
+        let mut by_def: HashMap<(TypstFileId, String), Vec<(TypstFileId, IdentRef)>> =
+            HashMap::new();
+        collect_import_sites(&info_a, a, &mut by_def);
+
+        let sites = by_def.get(&(b, "y".to_string())).expect("has entries for b::y");
+        let ranges: Vec<_> = sites.iter().map(|(fid, r)| (*fid, r.range.clone())).collect();
+        assert!(ranges.contains(&(a, import_site.range.clone())), "import site itself must be included");
+        assert!(ranges.contains(&(a, call_site_1.range.clone())), "later call site 1 must be included");
+        assert!(ranges.contains(&(a, call_site_2.range.clone())), "later call site 2 must be included");
+        assert_eq!(sites.len(), 3, "no extra or missing sites");
+    }
+}
+
+#[cfg(test)]
+mod rename_tests {
+    use super::test_support::*;
+    use super::*;
+
+    /// Renaming `x` (defined in `a.typ`) to `y`, where `a.typ` re-exports
+    /// that same binding under the name `y` already — this isn't a
+    /// collision, it's the binding being renamed seen through its own
+    /// export entry.
+    #[test]
+    fn no_collision_when_existing_export_is_the_renamed_symbol() {
+        let a = fid("/a.typ");
+
+        let mut info = DefUseInfo::default();
+        let (idx, _) = info
+            .ident_defs
+            .insert_full((a, IdentRef { name: "x".to_string(), range: 0..1 }), def(a, "x", Visibility::Public));
+        info.exports_defs.insert("y".to_string(), DefId(idx as u64));
+
+        assert!(!renaming_collides(&info, "y", a, "x"));
+    }
+
+    /// Renaming to a name that's already exported as a *different*
+    /// definition in the target file must be rejected.
+    #[test]
+    fn collision_when_existing_export_is_a_different_symbol() {
+        let a = fid("/a.typ");
+        let b = fid("/b.typ");
+
+        let mut info = DefUseInfo::default();
+        let (idx, _) = info.ident_defs.insert_full(
+            (b, IdentRef { name: "y".to_string(), range: 0..1 }),
+            def(b, "y", Visibility::Public),
+        );
+        info.exports_defs.insert("y".to_string(), DefId(idx as u64));
+
+        // Renaming a symbol defined in `a` to `y`, but this file's `y`
+        // export actually originates from `b`, not `a` — a real collision.
+        assert!(renaming_collides(&info, "y", a, "x"));
+    }
+}
+
+#[cfg(test)]
+mod worker_tests {
+    use super::test_support::*;
+    use super::*;
+
+    /// A burst of invalidations for two different files must both end up
+    /// dirty — collapsing to only the last message would silently drop
+    /// whichever file's edit arrived first.
+    #[test]
+    fn accumulates_invalidations_across_distinct_files() {
+        let a = fid("/a.typ");
+        let b = fid("/b.typ");
+
+        let mut dirty = HashSet::new();
+        accumulate_dirty(StateChange::Invalidate(a), &mut dirty);
+        accumulate_dirty(StateChange::Invalidate(b), &mut dirty);
+
+        assert_eq!(dirty, HashSet::from([a, b]));
+    }
+
+    /// A `Cancel` drops everything accumulated before it, so a cancel that
+    /// supersedes a run of invalidations actually cancels all of them.
+    #[test]
+    fn cancel_clears_previously_accumulated_dirty_set() {
+        let a = fid("/a.typ");
+
+        let mut dirty = HashSet::new();
+        accumulate_dirty(StateChange::Invalidate(a), &mut dirty);
+        accumulate_dirty(StateChange::Cancel, &mut dirty);
+
+        assert!(dirty.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod visibility_tests {
+    use super::test_support::*;
+    use super::*;
+
+    /// A public binding is visible from any importer.
+    #[test]
+    fn public_binding_is_visible_across_files() {
+        let owner = fid("/lib.typ");
+        let importer = fid("/main.typ");
+
+        let mut info = DefUseInfo::default();
+        let (idx, _) = info
+            .ident_defs
+            .insert_full((owner, IdentRef { name: "helper".to_string(), range: 0..6 }), def(owner, "helper", Visibility::Public));
+
+        assert!(info.is_visible_from(DefId(idx as u64), importer));
+        assert!(info.is_visible_from(DefId(idx as u64), owner));
+    }
+
+    /// A private binding is only visible within its own file.
+    #[test]
+    fn private_binding_is_hidden_from_other_files() {
+        let owner = fid("/lib.typ");
+        let importer = fid("/main.typ");
+
+        let mut info = DefUseInfo::default();
+        let (idx, _) = info.ident_defs.insert_full(
+            (owner, IdentRef { name: "_helper".to_string(), range: 0..7 }),
+            def(owner, "_helper", Visibility::Private),
+        );
+
+        assert!(!info.is_visible_from(DefId(idx as u64), importer));
+        assert!(info.is_visible_from(DefId(idx as u64), owner));
+    }
+}
+
+#[cfg(test)]
+mod symbol_index_tests {
+    use super::test_support::*;
+    use super::*;
+
+    fn info_with_export(fid: TypstFileId, name: &str) -> DefUseInfo {
+        let mut info = DefUseInfo::default();
+        let (idx, _) = info.ident_defs.insert_full(
+            (fid, IdentRef { name: name.to_string(), range: 0..name.len() }),
+            IdentDef {
+                name: name.to_string(),
+                kind: LexicalKind::Block,
+                fid,
+                range: 0..name.len(),
+                vis: Visibility::Public,
+            },
+        );
+        info.exports_defs.insert(name.to_string(), DefId(idx as u64));
+        info
+    }
+
+    #[test]
+    fn subsequence_score_rejects_out_of_order_or_missing_characters() {
+        assert!(subsequence_score("format", "fmt").is_some());
+        assert!(subsequence_score("format", "tmf").is_none());
+        assert!(subsequence_score("format", "z").is_none());
+    }
+
+    #[test]
+    fn subsequence_score_prefers_tighter_matches() {
+        let contiguous = subsequence_score("format", "for").unwrap();
+        let scattered = subsequence_score("format", "fmt").unwrap();
+        assert!(contiguous > scattered);
+    }
+
+    #[test]
+    fn query_finds_and_ranks_across_files() {
+        let a = fid("/a.typ");
+        let b = fid("/b.typ");
+
+        let mut index = SymbolIndex::default();
+        index.update(a, &info_with_export(a, "format-text"));
+        index.update(b, &info_with_export(b, "for-each"));
+
+        let results = index.query("for", |_| true);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].name, "for-each");
+    }
+
+    #[test]
+    fn remove_file_drops_only_that_files_symbols() {
+        let a = fid("/a.typ");
+        let b = fid("/b.typ");
+
+        let mut index = SymbolIndex::default();
+        index.update(a, &info_with_export(a, "alpha"));
+        index.update(b, &info_with_export(b, "beta"));
+
+        index.remove_file(a);
+
+        let results = index.query("", |_| true);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].fid, b);
+    }
+}